@@ -1,15 +1,167 @@
-use numpy::ndarray::{s, ArrayView1};
+use numpy::ndarray::{s, Array2, ArrayView1, ArrayView2};
 use numpy::PyReadonlyArray2;
 use pyo3::prelude::*;
+use rayon::prelude::*;
 
 use numpy::PyReadonlyArray1;
 
+fn l2_norm(a: &[f32]) -> f32 {
+    let sum_of_squares: f32 = a.iter().map(|&x| x * x).sum();
+    sum_of_squares.sqrt()
+}
+
+fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b.iter()).map(|(&x, &y)| x * y).sum();
+    let norm_a = l2_norm(a);
+    let norm_b = l2_norm(b);
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 1.0;
+    }
+    1.0 - dot / (norm_a * norm_b)
+}
+
+// Builds the per-beat feature vector (chroma column, optionally stacked with
+// the whitened timbre column) that `build_beat_jump_graph` compares beats on.
+fn beat_feature(
+    chroma: &ArrayView2<f32>,
+    whitened_timbre: &Option<Array2<f32>>,
+    beat: usize,
+) -> Vec<f32> {
+    let mut feature = chroma.slice(s![.., beat]).to_vec();
+    if let Some(timbre) = whitened_timbre {
+        feature.extend(timbre.slice(s![.., beat]).to_vec());
+    }
+    feature
+}
+
+// Pure computation behind `build_beat_jump_graph`, kept free of pyo3 types so
+// it can be exercised directly by unit tests.
+fn build_beat_jump_graph_impl(
+    chroma: ArrayView2<f32>,
+    whitened_timbre: Option<Array2<f32>>,
+    beats: &[usize],
+    jump_distance_threshold: f32,
+    min_beat_gap: usize,
+) -> Vec<Vec<(usize, f32)>> {
+    let n_beats = beats.len();
+
+    let beat_features: Vec<Vec<f32>> = (0..n_beats)
+        .map(|beat| beat_feature(&chroma, &whitened_timbre, beats[beat]))
+        .collect();
+
+    // A beat needs both neighbors present to judge whether its local
+    // neighborhood matches another beat's, so the two edge beats can never
+    // be jump sources/destinations.
+    if n_beats < 3 {
+        return vec![Vec::new(); n_beats];
+    }
+
+    (0..n_beats)
+        .into_par_iter()
+        .map(|i| {
+            let mut destinations: Vec<(usize, f32)> = Vec::new();
+            if i == 0 || i == n_beats - 1 {
+                return destinations;
+            }
+
+            let window_i = [
+                beat_features[i - 1].as_slice(),
+                beat_features[i].as_slice(),
+                beat_features[i + 1].as_slice(),
+            ]
+            .concat();
+
+            for j in 1..n_beats - 1 {
+                if j == i {
+                    continue;
+                }
+                let beat_gap = i.abs_diff(j);
+                if beat_gap < min_beat_gap.max(2) {
+                    continue;
+                }
+
+                let distance = cosine_distance(&beat_features[i], &beat_features[j]);
+                if distance > jump_distance_threshold {
+                    continue;
+                }
+
+                let window_j = [
+                    beat_features[j - 1].as_slice(),
+                    beat_features[j].as_slice(),
+                    beat_features[j + 1].as_slice(),
+                ]
+                .concat();
+                let neighborhood_distance = cosine_distance(&window_i, &window_j);
+                if neighborhood_distance <= jump_distance_threshold {
+                    destinations.push((j, neighborhood_distance));
+                }
+            }
+
+            destinations
+        })
+        .collect()
+}
+
+// Finds the first/last frame whose peak power is within `top_db` of the
+// track's loudest frame, mirroring the ecosystem's standard silence-trim
+// behavior so the beat scan doesn't anchor loops in dead air.
+fn silence_trim_bounds(power_db: ArrayView2<f32>, top_db: f32) -> (usize, usize) {
+    let n_frames = power_db.ncols();
+    let per_frame_peak: Vec<f32> = (0..n_frames)
+        .map(|frame| {
+            power_db
+                .slice(s![.., frame])
+                .iter()
+                .cloned()
+                .fold(f32::NEG_INFINITY, f32::max)
+        })
+        .collect();
+    let reference_peak = per_frame_peak
+        .iter()
+        .cloned()
+        .fold(f32::NEG_INFINITY, f32::max);
+    let threshold = reference_peak - top_db;
+
+    let start_frame = per_frame_peak
+        .iter()
+        .position(|&level| level >= threshold)
+        .unwrap_or(0);
+    let end_frame = per_frame_peak
+        .iter()
+        .rposition(|&level| level >= threshold)
+        .unwrap_or_else(|| n_frames.saturating_sub(1));
+    (start_frame, end_frame)
+}
+
+// Whitens each coefficient row (mean 0, unit variance) so no single timbre
+// coefficient dominates the distance just because it runs louder/quieter.
+// A constant row has no variance to scale and becomes all zeros.
+fn whiten_rows(matrix: ArrayView2<f32>) -> Array2<f32> {
+    let mut whitened = matrix.to_owned();
+    for mut row in whitened.rows_mut() {
+        let mean = row.iter().sum::<f32>() / row.len() as f32;
+        let variance = row.iter().map(|&x| (x - mean).powi(2)).sum::<f32>() / row.len() as f32;
+        let std_dev = variance.sqrt();
+        let std_dev = if std_dev == 0.0 { 1.0 } else { std_dev };
+        row.mapv_inplace(|x| (x - mean) / std_dev);
+    }
+    whitened
+}
+
+fn frame_distance(a: &[f32], b: &[f32], distance_metric: &str) -> f32 {
+    match distance_metric {
+        "cosine" => cosine_distance(a, b),
+        _ => l2_norm(
+            &a.iter()
+                .zip(b.iter())
+                .map(|(&x, &y)| x - y)
+                .collect::<Vec<f32>>(),
+        ),
+    }
+}
+
 #[pymodule]
 fn rust_analysis(_py: Python, m: &PyModule) -> PyResult<()> {
-    fn l2_norm(a: &[f32]) -> f32 {
-        let sum_of_squares: f32 = a.iter().map(|&x| x * x).sum();
-        sum_of_squares.sqrt()
-    }
     fn db_diff(power_db_f1: ArrayView1<f32>, power_db_f2: ArrayView1<f32>) -> f32 {
         let max_f1 = power_db_f1
             .iter()
@@ -22,6 +174,19 @@ fn rust_analysis(_py: Python, m: &PyModule) -> PyResult<()> {
         (max_f1 - max_f2).abs()
     }
     #[pyfn(m)]
+    #[pyo3(signature = (
+        chroma,
+        power_db,
+        beats,
+        acceptable_chroma_deviation,
+        min_loop_duration,
+        max_loop_duration,
+        acceptable_loudness_difference,
+        distance_metric = "euclidean",
+        timbre = None,
+        acceptable_timbre_deviation = None,
+        top_db = None
+    ))]
     fn detect_loop_pairs(
         chroma: PyReadonlyArray2<f32>,
         power_db: PyReadonlyArray2<f32>,
@@ -30,48 +195,283 @@ fn rust_analysis(_py: Python, m: &PyModule) -> PyResult<()> {
         min_loop_duration: usize,
         max_loop_duration: usize,
         acceptable_loudness_difference: f32,
-    ) -> Vec<(usize, usize, f32, f32)> {
-        let mut candidate_pairs: Vec<(usize, usize, f32, f32)> = Vec::new();
-
+        distance_metric: &str,
+        timbre: Option<PyReadonlyArray2<f32>>,
+        acceptable_timbre_deviation: Option<PyReadonlyArray1<f32>>,
+        top_db: Option<f32>,
+    ) -> Vec<(usize, usize, f32, f32, f32)> {
         let chroma = chroma.as_array();
         let power_db = power_db.as_array();
+        // Timbre gating is optional so existing callers that only pass chroma
+        // keep working unchanged; when omitted, every pair passes the gate.
+        let whitened_timbre = timbre.map(|timbre| whiten_rows(timbre.as_array()));
         let beats = beats.as_slice().unwrap_or(&[]);
+        // PyReadonlyArray1 itself is not Sync, so it can't be captured by the
+        // rayon closure below; the plain ArrayViews are.
+        let acceptable_chroma_deviation = acceptable_chroma_deviation.as_array();
+        let acceptable_timbre_deviation =
+            acceptable_timbre_deviation.map(|deviation| deviation.as_array().to_owned());
 
-        for (idx, &loop_end) in beats.iter().enumerate() {
-            for &loop_start in beats.iter() {
-                let loop_length = loop_end - loop_start;
-                if loop_length < min_loop_duration {
-                    break;
-                }
-                if loop_length > max_loop_duration {
-                    continue;
-                }
+        // Restrict the scan to beats inside the non-silent window so the search
+        // never anchors a loop point in leading/trailing silence.
+        let scan_indices: Vec<usize> = match top_db {
+            Some(top_db) => {
+                let (start_frame, end_frame) = silence_trim_bounds(power_db, top_db);
+                (0..beats.len())
+                    .filter(|&idx| beats[idx] >= start_frame && beats[idx] <= end_frame)
+                    .collect()
+            }
+            None => (0..beats.len()).collect(),
+        };
+        let scan_beats: Vec<usize> = scan_indices.iter().map(|&idx| beats[idx]).collect();
 
-                let note_distance = l2_norm(
-                    (&chroma.slice(s![.., loop_end]) - &chroma.slice(s![.., loop_start]))
-                        .as_slice()
-                        .unwrap_or(&[]),
-                );
+        // Each beat's `loop_end` scan is independent of every other, so the outer
+        // loop is farmed out to rayon; every worker accumulates into its own local
+        // Vec and the results are concatenated once all workers are done.
+        let candidate_pairs: Vec<(usize, usize, f32, f32, f32)> = scan_indices
+            .par_iter()
+            .map(|&idx| {
+                let loop_end = beats[idx];
+                let mut local_pairs: Vec<(usize, usize, f32, f32, f32)> = Vec::new();
+                let acceptable_chroma_deviation = acceptable_chroma_deviation[idx];
+                let acceptable_timbre_deviation = acceptable_timbre_deviation
+                    .as_ref()
+                    .map(|deviation| deviation[idx]);
+
+                for &loop_start in scan_beats.iter() {
+                    let loop_length = loop_end - loop_start;
+                    if loop_length < min_loop_duration {
+                        break;
+                    }
+                    if loop_length > max_loop_duration {
+                        continue;
+                    }
 
-                if note_distance <= *acceptable_chroma_deviation.get(idx).unwrap() {
-                    let loudness_difference = db_diff(
-                        power_db.slice(s![.., loop_end]),
-                        power_db.slice(s![.., loop_start]),
+                    let note_distance = frame_distance(
+                        &chroma.slice(s![.., loop_end]).to_vec(),
+                        &chroma.slice(s![.., loop_start]).to_vec(),
+                        distance_metric,
                     );
 
-                    if loudness_difference <= acceptable_loudness_difference {
-                        candidate_pairs.push((
-                            loop_start,
-                            loop_end,
-                            note_distance,
-                            loudness_difference,
-                        ));
+                    if note_distance <= acceptable_chroma_deviation {
+                        let timbre_distance = whitened_timbre.as_ref().map(|whitened_timbre| {
+                            frame_distance(
+                                &whitened_timbre.slice(s![.., loop_end]).to_vec(),
+                                &whitened_timbre.slice(s![.., loop_start]).to_vec(),
+                                distance_metric,
+                            )
+                        });
+
+                        let timbre_ok = match (timbre_distance, acceptable_timbre_deviation) {
+                            (Some(distance), Some(limit)) => distance <= limit,
+                            _ => true,
+                        };
+
+                        if timbre_ok {
+                            let loudness_difference = db_diff(
+                                power_db.slice(s![.., loop_end]),
+                                power_db.slice(s![.., loop_start]),
+                            );
+
+                            if loudness_difference <= acceptable_loudness_difference {
+                                // NaN (rather than 0.0) marks "not computed" so it can't
+                                // be mistaken for a genuine perfect timbre match.
+                                local_pairs.push((
+                                    loop_start,
+                                    loop_end,
+                                    note_distance,
+                                    loudness_difference,
+                                    timbre_distance.unwrap_or(f32::NAN),
+                                ));
+                            }
+                        }
                     }
                 }
-            }
-        }
+
+                local_pairs
+            })
+            .flatten()
+            .collect();
 
         return candidate_pairs;
     }
+    #[pyfn(m)]
+    #[pyo3(signature = (
+        chroma,
+        beats,
+        jump_distance_threshold,
+        min_beat_gap,
+        timbre = None
+    ))]
+    fn build_beat_jump_graph(
+        chroma: PyReadonlyArray2<f32>,
+        beats: PyReadonlyArray1<usize>,
+        jump_distance_threshold: f32,
+        min_beat_gap: usize,
+        timbre: Option<PyReadonlyArray2<f32>>,
+    ) -> Vec<Vec<(usize, f32)>> {
+        let whitened_timbre = timbre.map(|timbre| whiten_rows(timbre.as_array()));
+        build_beat_jump_graph_impl(
+            chroma.as_array(),
+            whitened_timbre,
+            beats.as_slice().unwrap_or(&[]),
+            jump_distance_threshold,
+            min_beat_gap,
+        )
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cosine_distance_is_zero_for_identical_vectors() {
+        assert!(cosine_distance(&[1.0, 2.0, 3.0], &[1.0, 2.0, 3.0]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cosine_distance_is_scale_invariant() {
+        let a = [1.0, 2.0, 3.0];
+        let b = [2.0, 4.0, 6.0];
+        assert!(cosine_distance(&a, &b).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cosine_distance_treats_a_zero_vector_as_maximally_distant() {
+        assert_eq!(cosine_distance(&[0.0, 0.0], &[1.0, 2.0]), 1.0);
+    }
+
+    #[test]
+    fn frame_distance_euclidean_matches_l2_norm_of_the_difference() {
+        let a = [1.0, 2.0, 3.0];
+        let b = [4.0, 2.0, 1.0];
+        // diff = [-3, 0, 2] -> sqrt(9 + 0 + 4) = sqrt(13)
+        assert!((frame_distance(&a, &b, "euclidean") - 13.0_f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn frame_distance_dispatches_to_cosine_distance() {
+        let a = [1.0, 2.0, 3.0];
+        let b = [2.0, 4.0, 6.0];
+        assert_eq!(frame_distance(&a, &b, "cosine"), cosine_distance(&a, &b));
+    }
+
+    #[test]
+    fn whiten_rows_gives_each_row_zero_mean_and_unit_variance() {
+        let matrix =
+            Array2::from_shape_vec((2, 4), vec![1.0, 2.0, 3.0, 4.0, 10.0, 10.0, 10.0, 10.0])
+                .unwrap();
+        let whitened = whiten_rows(matrix.view());
+
+        for row in whitened.rows() {
+            let mean = row.iter().sum::<f32>() / row.len() as f32;
+            let variance = row.iter().map(|&x| (x - mean).powi(2)).sum::<f32>() / row.len() as f32;
+            assert!(mean.abs() < 1e-5, "expected zero mean, got {mean}");
+            assert!(
+                (variance - 1.0).abs() < 1e-5 || variance.abs() < 1e-5,
+                "expected unit (or zero, for a constant row) variance, got {variance}"
+            );
+        }
+    }
+
+    #[test]
+    fn silence_trim_bounds_excludes_leading_and_trailing_silence() {
+        // 10 frames; peak power ramps up then back down, with the middle
+        // frames well above a 20dB-down threshold from the loudest frame.
+        let power_db = Array2::from_shape_fn((1, 10), |(_row, frame)| match frame {
+            0 | 1 | 8 | 9 => -80.0,
+            _ => -10.0,
+        });
+        let (start, end) = silence_trim_bounds(power_db.view(), 20.0);
+        assert_eq!(start, 2);
+        assert_eq!(end, 7);
+    }
+
+    // Column `frame` is `[frame, frame]`: the values differ per frame but every
+    // column points the same way, so the cosine distance between any two
+    // non-zero columns is 0 and nearly every beat pair passes the distance gate.
+    fn distinct_column_chroma(n_frames: usize) -> Array2<f32> {
+        Array2::from_shape_fn((2, n_frames), |(_row, frame)| frame as f32)
+    }
+
+    #[test]
+    fn edge_beats_never_appear_as_source_or_destination() {
+        let beats = [5usize, 10, 15, 20, 25];
+        let chroma = distinct_column_chroma(30);
+        let graph = build_beat_jump_graph_impl(chroma.view(), None, &beats, 10.0, 1);
+
+        assert!(graph[0].is_empty(), "first beat must never be a source");
+        assert!(
+            graph[beats.len() - 1].is_empty(),
+            "last beat must never be a source"
+        );
+        for destinations in &graph {
+            for &(dest, _) in destinations {
+                assert_ne!(dest, 0, "first beat must never be a destination");
+                assert_ne!(
+                    dest,
+                    beats.len() - 1,
+                    "last beat must never be a destination"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn min_beat_gap_is_respected() {
+        let beats = [0usize, 10, 20, 30, 40, 50];
+        let chroma = distinct_column_chroma(60);
+        let graph = build_beat_jump_graph_impl(chroma.view(), None, &beats, 1000.0, 3);
+
+        for (i, destinations) in graph.iter().enumerate() {
+            for &(j, _) in destinations {
+                let gap = i.abs_diff(j);
+                assert!(
+                    gap >= 3,
+                    "beat {i} jumped to {j} with gap {gap} < min_beat_gap"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn destinations_are_built_from_the_beats_frame_position_not_the_loop_index() {
+        // Every frame gets its own pseudo-random direction, except frames
+        // 50..=56 which are all pinned to the same vector. A regression that
+        // indexes chroma by the loop position `i`/`j` instead of the true frame
+        // `beats[i]`/`beats[j]` would instead compare the (near-certainly
+        // distinct) directions at frames 0..=6 and find no jumps.
+        let n_frames = 60;
+        let chroma = Array2::from_shape_fn((4, n_frames), |(row, frame)| {
+            if (50..57).contains(&frame) {
+                1.0
+            } else {
+                let x = frame as f32 + row as f32;
+                match row {
+                    0 => (x * 2.1).sin(),
+                    1 => (x * 1.3).cos(),
+                    2 => (x * 0.7).sin(),
+                    _ => (x * 3.3).cos(),
+                }
+            }
+        });
+
+        let beats = [50usize, 51, 52, 53, 54, 55, 56];
+        let graph = build_beat_jump_graph_impl(chroma.view(), None, &beats, 0.0001, 2);
+
+        assert!(
+            !graph[3].is_empty(),
+            "expected the middle beat to find a jump among beats built from its true chroma columns"
+        );
+        for &(dest, distance) in &graph[3] {
+            assert!(
+                distance < 0.0001,
+                "beat built from frame {} should match beat built from frame {} almost exactly, got distance {distance}",
+                beats[3],
+                beats[dest]
+            );
+        }
+    }
+}